@@ -0,0 +1,174 @@
+//! On-disk cache for fetched feeds, so a daemon tick (or a quick repeated
+//! CLI run) doesn't re-download and re-parse the full JSON every time.
+//!
+//! Each cached entry stores the response body alongside its ETag and its
+//! parsed `syncToken`/`creationTime`-style token. Refetching sends the
+//! ETag back as `If-None-Match`; a `304 Not Modified` response means the
+//! cached body is still current and is returned without hitting the network
+//! again. This also lets the tool fall back to the last good snapshot when
+//! offline, since `load` works without a network round-trip at all.
+
+use crate::BlockError;
+use std::path::PathBuf;
+
+/// one provider's cached feed: its raw body plus the bits needed to make a
+/// conditional request and to detect whether the feed actually changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub etag: Option<String>,
+    pub sync_token: Option<String>,
+    pub body: String,
+}
+
+/// result of a conditional fetch: the body to parse, and whether it is
+/// unchanged from what's already cached (the server said `304 Not Modified`
+/// or could not be reached and the cached body was used, or the refetched
+/// body's `syncToken` matched the cached one)
+#[derive(Debug, Clone)]
+pub struct CachedFetch {
+    pub body: String,
+    pub unchanged: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        let safe_key: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        self.dir.join(format!("{}.json", safe_key))
+    }
+
+    pub fn load(&self, key: &str) -> Option<CacheEntry> {
+        let contents = std::fs::read_to_string(self.entry_path(key)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn store(&self, key: &str, entry: &CacheEntry) -> Result<(), BlockError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_string(entry)?;
+        std::fs::write(self.entry_path(key), json)?;
+        Ok(())
+    }
+
+    /// fetches `url`, using the cached entry under `key` (if any) to make a
+    /// conditional request; `force_refresh` ignores any cached entry (the
+    /// fresh response is still stored). When the request cannot be sent
+    /// (e.g. offline), the cached body is returned if there is one.
+    pub async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        key: &str,
+        force_refresh: bool,
+    ) -> Result<CachedFetch, BlockError> {
+        let cached = if force_refresh { None } else { self.load(key) };
+
+        let mut request = client.get(url);
+        if let Some(entry) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            // unreachable server: fall back to the last good snapshot
+            Err(e) => match cached {
+                Some(entry) => return Ok(CachedFetch { body: entry.body, unchanged: true }),
+                None => return Err(e.into()),
+            },
+        };
+
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return not_modified_fetch(key, cached);
+        }
+
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let body = response.text().await?;
+        let sync_token = extract_sync_token(&body);
+        let unchanged = sync_token_unchanged(cached.as_ref(), sync_token.as_deref());
+
+        self.store(key, &CacheEntry { etag, sync_token, body: body.clone() })?;
+        Ok(CachedFetch { body, unchanged })
+    }
+}
+
+/// handles a `304 Not Modified` response: the cached body is still current,
+/// unless there's nothing cached to fall back on (a `304` against a request
+/// we didn't actually send `If-None-Match` on, e.g. the cache was wiped out
+/// from under a still-ETag-aware server)
+fn not_modified_fetch(key: &str, cached: Option<CacheEntry>) -> Result<CachedFetch, BlockError> {
+    let entry = cached.ok_or_else(|| {
+        BlockError::CacheError(format!("server returned 304 for {} but nothing is cached", key))
+    })?;
+    Ok(CachedFetch { body: entry.body, unchanged: true })
+}
+
+/// a freshly-fetched body is "unchanged" only when there was a previous
+/// entry *and* it carried a `syncToken` that matches the new one; an absent
+/// or mismatched token means treat it as changed
+fn sync_token_unchanged(cached: Option<&CacheEntry>, sync_token: Option<&str>) -> bool {
+    cached.map_or(false, |prev| prev.sync_token.is_some() && prev.sync_token.as_deref() == sync_token)
+}
+
+/// pulls the `syncToken` field out of a feed body without fully parsing it
+/// into `AWSRange`/`GoogleRange`; both feeds expose it under this key
+fn extract_sync_token(body: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(body).ok()?;
+    value.get("syncToken")?.as_str().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sync_token: Option<&str>) -> CacheEntry {
+        CacheEntry { etag: None, sync_token: sync_token.map(String::from), body: String::new() }
+    }
+
+    #[test]
+    fn not_modified_without_cached_entry_is_an_error() {
+        let err = not_modified_fetch("amazon", None).unwrap_err();
+        assert!(matches!(err, BlockError::CacheError(_)));
+    }
+
+    #[test]
+    fn not_modified_with_cached_entry_returns_its_body_as_unchanged() {
+        let cached = CacheEntry { body: String::from("cached-body"), ..entry(Some("token-1")) };
+        let fetched = not_modified_fetch("amazon", Some(cached)).unwrap();
+        assert_eq!(fetched.body, "cached-body");
+        assert!(fetched.unchanged);
+    }
+
+    #[test]
+    fn sync_token_match_is_unchanged() {
+        let cached = entry(Some("token-1"));
+        assert!(sync_token_unchanged(Some(&cached), Some("token-1")));
+    }
+
+    #[test]
+    fn sync_token_mismatch_is_not_unchanged() {
+        let cached = entry(Some("token-1"));
+        assert!(!sync_token_unchanged(Some(&cached), Some("token-2")));
+    }
+
+    #[test]
+    fn absent_sync_token_is_not_unchanged() {
+        let cached = entry(None);
+        assert!(!sync_token_unchanged(Some(&cached), None));
+        assert!(!sync_token_unchanged(None, None));
+    }
+}
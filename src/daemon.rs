@@ -0,0 +1,145 @@
+//! Long-running mode that keeps a [`Blocker`] current as cloud providers
+//! rotate their ranges: on each tick it re-fetches, reconciles the delta
+//! against what's already applied, and persists the result so a restart
+//! reconciles instead of re-adding everything from scratch.
+//!
+//! Readiness/status is reported the way the ipblc-style blockers do it, via
+//! the sd_notify protocol (a datagram to `$NOTIFY_SOCKET`) rather than a
+//! dependency on systemd itself, so this is a no-op outside a unit with
+//! `Type=notify`.
+
+use crate::{BlockError, Blocker, IpPrefix};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// runs the refresh loop forever, calling `fetch` on the given `interval`.
+///
+/// `state_path` holds the last-applied prefix set as JSON; on startup, if
+/// it's present, the blocker is seeded with it via [`Blocker::set_applied`]
+/// so the first reconcile only applies what's actually changed since the
+/// last run instead of re-adding everything that's already blocked.
+///
+/// `fetch` returns `Ok(None)` when the caller (typically a [`crate::Cache`]
+/// consumer) determined the feed hasn't changed since the last fetch, e.g.
+/// its `syncToken` matched; that tick skips `reconcile` entirely instead of
+/// recomputing a diff that's known to be empty. The one exception is the
+/// very first tick of this process: the feed cache and `state_path` both
+/// survive a restart, but the firewall itself doesn't (a crash or reboot
+/// wipes the actual `iptables`/`ipset`/`nft` state), so an "unchanged"
+/// result there would otherwise report `READY=1`/blocking N prefixes while
+/// nothing is installed at all. So when the feed is unchanged on that first
+/// tick, it applies [`Blocker::block`] for whatever prefix set is currently
+/// known (the persisted one, if any); a changed feed on the first tick goes
+/// through `reconcile` like any other tick.
+///
+/// watchdog pings run on their own timer (see [`watchdog_interval`]),
+/// independent of `interval`, since a `WatchdogSec=` shorter than the
+/// refresh cadence would otherwise get the daemon killed before its first
+/// tick completes.
+pub async fn run<F, Fut>(
+    mut blocker: Blocker,
+    interval: Duration,
+    state_path: PathBuf,
+    mut fetch: F,
+) -> Result<(), BlockError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Option<Vec<IpPrefix>>, BlockError>>,
+{
+    if let Ok(contents) = std::fs::read_to_string(&state_path) {
+        if let Ok(persisted) = serde_json::from_str(&contents) {
+            blocker.set_applied(persisted);
+        }
+    }
+
+    if let Some(wd_interval) = watchdog_interval() {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(wd_interval).await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+
+    let mut first_tick = true;
+    let mut applied_this_process = false;
+    loop {
+        if !first_tick {
+            notify("RELOADING=1");
+        }
+
+        if let Some(fresh) = fetch().await? {
+            blocker.reconcile(fresh).await?;
+            persist_state(&state_path, blocker.applied())?;
+            applied_this_process = true;
+        } else if !applied_this_process {
+            // the cache says the feed is unchanged, but nothing has actually
+            // been applied to the firewall by this process yet - force a
+            // real (re)install of the currently-known prefix set rather than
+            // trusting stale in-kernel state that may not exist anymore
+            blocker.block().await?;
+            applied_this_process = true;
+        }
+
+        notify("READY=1");
+        notify(&format!(
+            "STATUS=blocking {} prefixes (last update {})",
+            blocker.applied().len(),
+            unix_now()
+        ));
+        first_tick = false;
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// the watchdog ping cadence systemd expects: half of `$WATCHDOG_USEC`,
+/// which it sets from the unit's `WatchdogSec=` when running under
+/// `Type=notify`. `None` when no watchdog is configured (the env var is
+/// unset, e.g. no `WatchdogSec=` or not running under systemd at all); a
+/// ping tied to the (typically much longer) refresh `interval` instead would
+/// let systemd's watchdog timeout fire before the next tick ever ran.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+fn persist_state(path: &Path, ips: &[IpPrefix]) -> Result<(), BlockError> {
+    let json = serde_json::to_string(ips)?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// best-effort sd_notify: sends `state` as a datagram to `$NOTIFY_SOCKET`.
+/// Silently does nothing when the variable isn't set (not running under a
+/// notify-type systemd unit) and logs rather than fails on socket errors,
+/// since a refresh should not abort over a notification that didn't land.
+///
+/// Does not handle the Linux abstract-namespace form of `$NOTIFY_SOCKET`
+/// (a leading `@` standing in for a NUL byte) since that requires raw
+/// `sockaddr_un` construction beyond what `std::os::unix::net` exposes;
+/// the common filesystem-path socket systemd sets up works as-is.
+fn notify(state: &str) {
+    let socket_path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = send_notify(&socket_path, state) {
+        eprintln!("sd_notify({}) failed: {}", state, e);
+    }
+}
+
+fn send_notify(socket_path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(state.as_bytes(), socket_path)?;
+    Ok(())
+}
@@ -32,8 +32,22 @@ pub enum BlockError {
     Utf8Error(#[source] std::string::FromUtf8Error),
     #[error(display = "{}", _0)]
     ParseIntError(#[source] std::num::ParseIntError),
+    #[error(display = "prefix length {} exceeds address width of {} bits", _0, _1)]
+    InvalidPrefixLength(u8, u8),
+    #[error(display = "toml parsing error")]
+    TomlError(#[source] toml::de::Error),
+    #[error(display = "http request error")]
+    ReqwestError(#[source] reqwest::Error),
+    #[error(display = "cache error: {}", _0)]
+    CacheError(String),
 }
 
+mod cache;
+pub use cache::{Cache, CacheEntry, CachedFetch};
+mod config;
+pub use config::{Config, DynamicRange, FieldMapping, ProviderConfig};
+pub mod daemon;
+
 /*impl From<std::option::NoneError> for BlockError {
     fn from(_: std::option::NoneError) -> Self {
         Self::NoneError
@@ -44,6 +58,35 @@ pub trait Range {
     type Err: std::error::Error;
     fn prefix_count(&self) -> usize;
     fn prefixes(self) -> Result<Vec<IpPrefix>, Self::Err>;
+    /// like [`Range::prefixes`], but drops any entry that doesn't match `filter`
+    fn prefixes_filtered(self, filter: &PrefixFilter) -> Result<Vec<IpPrefix>, Self::Err>;
+}
+
+/// optional allow-sets for a range's metadata fields; a `None` field admits
+/// everything, an empty (but present) set admits nothing
+#[derive(Debug, Clone, Default)]
+pub struct PrefixFilter {
+    pub regions: Option<std::collections::HashSet<String>>,
+    pub services: Option<std::collections::HashSet<String>>,
+    pub scopes: Option<std::collections::HashSet<String>>,
+}
+
+impl PrefixFilter {
+    /// an entry passes when every set present in the filter contains the
+    /// entry's corresponding field; a field the entry doesn't have (`None`)
+    /// fails any filter set that was specified for it
+    fn matches(&self, region: Option<&str>, service: Option<&str>, scope: Option<&str>) -> bool {
+        Self::field_matches(&self.regions, region)
+            && Self::field_matches(&self.services, service)
+            && Self::field_matches(&self.scopes, scope)
+    }
+
+    fn field_matches(allow: &Option<std::collections::HashSet<String>>, value: Option<&str>) -> bool {
+        match allow {
+            None => true,
+            Some(allowed) => value.map_or(false, |v| allowed.contains(v)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,6 +108,15 @@ impl Range for AWSRange {
         }
         Ok(outvec)
     }
+    fn prefixes_filtered(self, filter: &PrefixFilter) -> Result<Vec<IpPrefix>, Self::Err> {
+        let mut outvec = Vec::new();
+        for p in self.prefixes.into_iter() {
+            if filter.matches(Some(&p.region), Some(&p.service), None) {
+                outvec.push(p.try_to_prefix()?);
+            }
+        }
+        Ok(outvec)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +159,15 @@ impl Range for GoogleRange {
         }
         Ok(outvec)
     }
+    fn prefixes_filtered(self, filter: &PrefixFilter) -> Result<Vec<IpPrefix>, Self::Err> {
+        let mut outvec = Vec::new();
+        for p in self.prefixes.into_iter() {
+            if filter.matches(None, p.service.as_deref(), p.scope.as_deref()) {
+                outvec.push(p.try_to_prefix()?);
+            }
+        }
+        Ok(outvec)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -129,7 +190,7 @@ impl GoogleIp {
     }
 }
 
-#[derive(Copy, Debug, Clone, PartialEq, Eq)]
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct V4Prefix {
     ip: [u8; 4],
     prefix: u8,
@@ -149,6 +210,9 @@ impl FromStr for V4Prefix {
             None => return Err(BlockError::NoneError),
         }.parse::<u8>()?;
         //print!("prefix: {} ", prefix);
+        if prefix > 32 {
+            return Err(BlockError::InvalidPrefixLength(prefix, 32));
+        }
         Ok(Self { ip, prefix })
     }
 }
@@ -202,7 +266,7 @@ impl<'de> Deserialize<'de> for V4Prefix {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct V6Prefix {
     ip: [u16; 8],
     prefix: u8,
@@ -220,6 +284,9 @@ impl FromStr for V6Prefix {
             Some(value) => value,
             None => return Err(BlockError::NoneError),
         }.parse::<u8>()?;
+        if prefix > 128 {
+            return Err(BlockError::InvalidPrefixLength(prefix, 128));
+        }
         Ok(Self { ip, prefix })
     }
 }
@@ -277,7 +344,7 @@ impl<'de> Deserialize<'de> for V6Prefix {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum IpPrefix {
     V4(V4Prefix),
     V6(V6Prefix),
@@ -293,59 +360,163 @@ impl fmt::Display for IpPrefix {
     }
 }
 
+/// name of the ipv4 ipset/nftables set bigblocker installs its rule against
+const SET_NAME_V4: &str = "bigblocker_v4";
+/// name of the ipv6 ipset/nftables set bigblocker installs its rule against
+const SET_NAME_V6: &str = "bigblocker_v6";
+/// name of the nftables table bigblocker owns
+const NFT_TABLE: &str = "bigblocker";
+
+/// selects how `Blocker` turns a prefix list into firewall state.
+///
+/// `Iptables` is the historical behavior: one rule per prefix. For large
+/// feeds (tens of thousands of prefixes) that spawns a process per prefix
+/// and builds a chain that's scanned linearly on every packet, so `Ipset`
+/// and `Nftables` exist to push the matching into a kernel set instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Backend {
+    /// one `iptables`/`ip6tables` rule per prefix
+    Iptables,
+    /// a single `ipset` hash:net set per address family, matched by one rule each
+    Ipset,
+    /// a single `nft` script defining a set per address family and one drop rule each
+    Nftables,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Iptables
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Blocker {
     ips: Vec<IpPrefix>,
     save: bool,
+    backend: Backend,
 }
 
 impl Blocker {
     /// note save may become a unix only option with windows defaulting to true
-    pub fn new(ips: Vec<IpPrefix>, save: bool) -> Self {
-        Self { ips, save }
+    pub fn new(ips: Vec<IpPrefix>, save: bool, backend: Backend) -> Self {
+        Self { ips, save, backend }
+    }
+    /// collapses overlapping and adjacent prefixes in place; see [`aggregate`]
+    pub fn aggregate(&mut self) {
+        self.ips = aggregate(std::mem::take(&mut self.ips));
+    }
+    /// the prefixes this blocker currently believes are applied
+    pub fn applied(&self) -> &[IpPrefix] {
+        &self.ips
+    }
+    /// seeds the blocker's notion of "currently applied" without touching
+    /// the firewall; used by [`daemon`] to reconcile against a persisted
+    /// state file instead of re-adding everything that's already blocked
+    /// after a restart
+    pub fn set_applied(&mut self, ips: Vec<IpPrefix>) {
+        self.ips = ips;
+    }
+    /// refreshes to `new_ips`, applying only the add/remove delta against
+    /// the previously-applied set rather than flushing and reblocking
+    /// everything.
+    ///
+    /// when nothing is currently tracked (a fresh daemon start with no
+    /// persisted state), there's no delta to take: this installs `new_ips`
+    /// via the batch [`Blocker::block`] path instead of one `add_one` call
+    /// per prefix, so `Ipset`/`Nftables` get their set/table created rather
+    /// than erroring against one that doesn't exist yet
+    pub async fn reconcile(&mut self, new_ips: Vec<IpPrefix>) -> Result<(), BlockError> {
+        if self.ips.is_empty() {
+            self.ips = new_ips;
+            return self.block().await;
+        }
+        use std::collections::HashSet;
+        let old: HashSet<&IpPrefix> = self.ips.iter().collect();
+        let new: HashSet<&IpPrefix> = new_ips.iter().collect();
+        let removed: Vec<IpPrefix> = old.difference(&new).map(|ip| (*ip).clone()).collect();
+        let added: Vec<IpPrefix> = new.difference(&old).map(|ip| (*ip).clone()).collect();
+        for ip in &removed {
+            self.remove_one(ip).await?;
+        }
+        for ip in &added {
+            self.add_one(ip).await?;
+        }
+        self.ips = new_ips;
+        Ok(())
+    }
+
+    async fn add_one(&self, ip: &IpPrefix) -> Result<(), BlockError> {
+        match self.backend {
+            Backend::Iptables => {
+                run_command(
+                    iptables_program(ip),
+                    &["-A", "OUTPUT", "-d", &format!("{}", ip), "-j", "DROP"],
+                )
+                .await
+            }
+            Backend::Ipset => run_command("ipset", &["add", set_name(ip), &format!("{}", ip)]).await,
+            Backend::Nftables => {
+                run_nft(&format!("add element inet {} {} {{ {} }}\n", NFT_TABLE, set_name(ip), ip)).await
+            }
+        }
+    }
+
+    async fn remove_one(&self, ip: &IpPrefix) -> Result<(), BlockError> {
+        match self.backend {
+            Backend::Iptables => {
+                run_command(
+                    iptables_program(ip),
+                    &["-D", "OUTPUT", "-d", &format!("{}", ip), "-j", "DROP"],
+                )
+                .await
+            }
+            Backend::Ipset => run_command("ipset", &["del", set_name(ip), &format!("{}", ip)]).await,
+            Backend::Nftables => {
+                run_nft(&format!("delete element inet {} {} {{ {} }}\n", NFT_TABLE, set_name(ip), ip)).await
+            }
+        }
     }
     /// this will handle actually blocking ip addresses
     pub async fn block(&self) -> Result<(), BlockError> {
+        if cfg!(target_os = "windows") {
+            return self.block_windows().await;
+        }
+        match self.backend {
+            Backend::Iptables => self.block_iptables().await,
+            Backend::Ipset => self.block_ipset().await,
+            Backend::Nftables => self.block_nftables().await,
+        }
+    }
+
+    async fn block_windows(&self) -> Result<(), BlockError> {
         for ip in self.ips.iter() {
-            if cfg!(target_os = "linux") || cfg!(target_os = "macos") {
-                // replace OUTPUT with FORWARD if blocking NAT
-                let output = tokio::process::Command::new("iptables")
-                    .args(&["-A", "OUTPUT", "-d", &format!("{}", ip), "-j", "DROP"])
-                    .output()
-                    .await?;
-                if !output.status.success() {
-                    let code = output.status.code().unwrap();
-                    let stderr = String::from_utf8(output.stderr)?;
-                    return Err(BlockError::CommandFailed((stderr, code)));
-                }
-            }
-            if cfg!(target_os = "windows") {
-                let output = tokio::process::Command::new("netsh").args(&[
-                    "advfirewall",
-                    "firewall",
-                    "add",
-                    "rule",
-                    "name=\"BigBlocker\"",
-                    "dir=out",
-                    "action=deny",
-                    "enable=yes",
-                    &format!("remoteip={}", ip),
-                    "profile=public"
-                ]).output().await?;
-                if !output.status.success() {
-                    let code = output.status.code().unwrap();
-                    let stdout = String::from_utf8(output.stdout)?;
-                    return Err(BlockError::CommandFailed((stdout, code)));
-                }
+            let output = tokio::process::Command::new("netsh").args(&[
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                "name=\"BigBlocker\"",
+                "dir=out",
+                "action=deny",
+                "enable=yes",
+                &format!("remoteip={}", ip),
+                "profile=public"
+            ]).output().await?;
+            if !output.status.success() {
+                let code = output.status.code().unwrap();
+                let stdout = String::from_utf8(output.stdout)?;
+                return Err(BlockError::CommandFailed((stdout, code)));
             }
         }
         Ok(())
     }
-    /// resets firewall rules
-    pub async fn unblock_all() -> Result<(), BlockError>{
-        if cfg!(target_os = "linux") || cfg!(target_os = "macos") {
+
+    /// legacy behavior: one `iptables -A OUTPUT ... -j DROP` call per prefix
+    async fn block_iptables(&self) -> Result<(), BlockError> {
+        for ip in self.ips.iter() {
+            // replace OUTPUT with FORWARD if blocking NAT
             let output = tokio::process::Command::new("iptables")
-                .args(&["-F", "OUTPUT"])
+                .args(&["-A", "OUTPUT", "-d", &format!("{}", ip), "-j", "DROP"])
                 .output()
                 .await?;
             if !output.status.success() {
@@ -354,6 +525,35 @@ impl Blocker {
                 return Err(BlockError::CommandFailed((stderr, code)));
             }
         }
+        Ok(())
+    }
+
+    /// loads every prefix into two `ipset` hash:net sets (v4/v6) in a single
+    /// `ipset restore` batch, then installs exactly one matching rule per set
+    async fn block_ipset(&self) -> Result<(), BlockError> {
+        run_ipset_restore(&ipset_restore_script(&self.ips)).await?;
+
+        run_command(
+            "iptables",
+            &["-A", "OUTPUT", "-m", "set", "--match-set", SET_NAME_V4, "dst", "-j", "DROP"],
+        )
+        .await?;
+        run_command(
+            "ip6tables",
+            &["-A", "OUTPUT", "-m", "set", "--match-set", SET_NAME_V6, "dst", "-j", "DROP"],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// emits a single `nft -f -` script defining a named set per address
+    /// family plus one drop rule each, and loads it in one `nft` call
+    async fn block_nftables(&self) -> Result<(), BlockError> {
+        run_nft(&nftables_script(&self.ips)).await
+    }
+
+    /// resets firewall rules
+    pub async fn unblock_all(backend: Backend) -> Result<(), BlockError> {
         if cfg!(target_os = "windows") {
             let output = tokio::process::Command::new("netsh")
                 .args(&["advfirewall", "reset"])
@@ -364,7 +564,445 @@ impl Blocker {
                 let stdout = String::from_utf8(output.stdout)?;
                 return Err(BlockError::CommandFailed((stdout, code)));
             }
+            return Ok(());
+        }
+
+        match backend {
+            Backend::Iptables => {
+                run_command("iptables", &["-F", "OUTPUT"]).await?;
+            }
+            Backend::Ipset => {
+                // the rule must go before the set, or the kernel refuses to
+                // destroy a set that's still referenced by a rule
+                let _ = run_command(
+                    "iptables",
+                    &["-D", "OUTPUT", "-m", "set", "--match-set", SET_NAME_V4, "dst", "-j", "DROP"],
+                )
+                .await;
+                let _ = run_command(
+                    "ip6tables",
+                    &["-D", "OUTPUT", "-m", "set", "--match-set", SET_NAME_V6, "dst", "-j", "DROP"],
+                )
+                .await;
+                let _ = run_command("ipset", &["destroy", SET_NAME_V4]).await;
+                let _ = run_command("ipset", &["destroy", SET_NAME_V6]).await;
+            }
+            Backend::Nftables => {
+                // best-effort, like the Ipset arm above: resetting when the
+                // table was never created (first run, or already reset)
+                // should be a no-op, not a hard failure
+                let _ = run_nft(&format!("delete table inet {}\n", NFT_TABLE)).await;
+            }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// the iptables binary that matches a prefix's address family
+fn iptables_program(ip: &IpPrefix) -> &'static str {
+    match ip {
+        IpPrefix::V4(_) => "iptables",
+        IpPrefix::V6(_) => "ip6tables",
+    }
+}
+
+/// the ipset/nftables set that holds a prefix's address family
+fn set_name(ip: &IpPrefix) -> &'static str {
+    match ip {
+        IpPrefix::V4(_) => SET_NAME_V4,
+        IpPrefix::V6(_) => SET_NAME_V6,
+    }
+}
+
+/// builds the `ipset restore` stdin batch: two hash:net sets plus one `add`
+/// line per prefix, routed to the matching family
+fn ipset_restore_script(ips: &[IpPrefix]) -> String {
+    let mut script = String::new();
+    script.push_str(&format!("create {} hash:net family inet -exist\n", SET_NAME_V4));
+    script.push_str(&format!("create {} hash:net family inet6 -exist\n", SET_NAME_V6));
+    for ip in ips {
+        match ip {
+            IpPrefix::V4(v4) => script.push_str(&format!("add {} {}\n", SET_NAME_V4, v4)),
+            IpPrefix::V6(v6) => script.push_str(&format!("add {} {}\n", SET_NAME_V6, v6)),
+        }
+    }
+    script
+}
+
+/// builds a single `nft -f -` script: one table holding a set per address
+/// family, and an output chain dropping anything matched by either set
+fn nftables_script(ips: &[IpPrefix]) -> String {
+    let v4: Vec<String> = ips
+        .iter()
+        .filter_map(|ip| match ip {
+            IpPrefix::V4(v4) => Some(format!("{}", v4)),
+            IpPrefix::V6(_) => None,
+        })
+        .collect();
+    let v6: Vec<String> = ips
+        .iter()
+        .filter_map(|ip| match ip {
+            IpPrefix::V6(v6) => Some(format!("{}", v6)),
+            IpPrefix::V4(_) => None,
+        })
+        .collect();
+
+    format!(
+        "table inet {table} {{\n\
+        \u{20}\u{20}set {v4_set} {{\n\
+        \u{20}\u{20}\u{20}\u{20}type ipv4_addr; flags interval;\n\
+        \u{20}\u{20}\u{20}\u{20}elements = {{ {v4_elems} }}\n\
+        \u{20}\u{20}}}\n\
+        \u{20}\u{20}set {v6_set} {{\n\
+        \u{20}\u{20}\u{20}\u{20}type ipv6_addr; flags interval;\n\
+        \u{20}\u{20}\u{20}\u{20}elements = {{ {v6_elems} }}\n\
+        \u{20}\u{20}}}\n\
+        \u{20}\u{20}chain output {{\n\
+        \u{20}\u{20}\u{20}\u{20}type filter hook output priority 0;\n\
+        \u{20}\u{20}\u{20}\u{20}ip daddr @{v4_set} drop\n\
+        \u{20}\u{20}\u{20}\u{20}ip6 daddr @{v6_set} drop\n\
+        \u{20}\u{20}}}\n\
+        }}\n",
+        table = NFT_TABLE,
+        v4_set = SET_NAME_V4,
+        v6_set = SET_NAME_V6,
+        v4_elems = v4.join(", "),
+        v6_elems = v6.join(", "),
+    )
+}
+
+/// runs `ipset restore`, feeding it `script` on stdin
+async fn run_ipset_restore(script: &str) -> Result<(), BlockError> {
+    run_piped("ipset", &["restore"], script).await
+}
+
+/// runs `nft -f -`, feeding it `script` on stdin
+async fn run_nft(script: &str) -> Result<(), BlockError> {
+    run_piped("nft", &["-f", "-"], script).await
+}
+
+/// runs `program args...`, writing `stdin` to its standard input
+async fn run_piped(program: &str, args: &[&str], stdin: &str) -> Result<(), BlockError> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or(BlockError::NoneError)?
+        .write_all(stdin.as_bytes())
+        .await?;
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let code = output.status.code().unwrap();
+        let stderr = String::from_utf8(output.stderr)?;
+        return Err(BlockError::CommandFailed((stderr, code)));
+    }
+    Ok(())
+}
+
+/// runs `program args...` with no stdin, erroring on a non-zero exit
+async fn run_command(program: &str, args: &[&str]) -> Result<(), BlockError> {
+    let output = tokio::process::Command::new(program).args(args).output().await?;
+    if !output.status.success() {
+        let code = output.status.code().unwrap();
+        let stderr = String::from_utf8(output.stderr)?;
+        return Err(BlockError::CommandFailed((stderr, code)));
+    }
+    Ok(())
+}
+/// minimizes a prefix list by merging overlapping/adjacent ranges and
+/// re-emitting the smallest set of aligned CIDR blocks that covers them.
+///
+/// v4 and v6 prefixes are aggregated independently; the result order is all
+/// merged v4 blocks followed by all merged v6 blocks.
+pub fn aggregate(prefixes: Vec<IpPrefix>) -> Vec<IpPrefix> {
+    let mut v4s = Vec::new();
+    let mut v6s = Vec::new();
+    for p in prefixes {
+        match p {
+            IpPrefix::V4(v4) => v4s.push(v4),
+            IpPrefix::V6(v6) => v6s.push(v6),
+        }
+    }
+    let mut out: Vec<IpPrefix> = aggregate_v4(v4s).into_iter().map(IpPrefix::V4).collect();
+    out.extend(aggregate_v6(v6s).into_iter().map(IpPrefix::V6));
+    out
+}
+
+fn v4_interval(p: &V4Prefix) -> (u32, u32) {
+    let prefix = p.prefix.min(32);
+    let ip = u32::from_be_bytes(p.ip);
+    let netmask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+    let start = ip & netmask;
+    (start, start | !netmask)
+}
+
+/// merges a list of `(start, end)` intervals (inclusive), treating
+/// overlapping *and* adjacent (`start <= prev_end + 1`) intervals as one
+fn merge_intervals_u32(mut intervals: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    intervals.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start as u64 <= last.1 as u64 + 1 => {
+                if end > last.1 {
+                    last.1 = end;
+                }
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// re-decomposes a merged `[start, end]` range into the minimal set of
+/// aligned CIDR blocks: repeatedly emit the largest block that's both a
+/// power of two no bigger than the remaining span and aligned to `start`
+fn decompose_v4(start: u32, end: u32) -> Vec<V4Prefix> {
+    if start == 0 && end == u32::MAX {
+        return vec![V4Prefix { ip: [0, 0, 0, 0], prefix: 0 }];
+    }
+    let mut out = Vec::new();
+    let mut start = start;
+    loop {
+        let align_bits = if start == 0 { 32 } else { start.trailing_zeros() };
+        let mut prefix_len = 32u32.saturating_sub(align_bits).max(1);
+        let block_size = loop {
+            let block_size = 1u64 << (32 - prefix_len);
+            if start as u64 + block_size - 1 <= end as u64 {
+                break block_size;
+            }
+            prefix_len += 1;
+        };
+        out.push(V4Prefix { ip: start.to_be_bytes(), prefix: prefix_len as u8 });
+        let next = start as u64 + block_size;
+        if next > end as u64 {
+            break;
+        }
+        start = next as u32;
+    }
+    out
+}
+
+fn aggregate_v4(prefixes: Vec<V4Prefix>) -> Vec<V4Prefix> {
+    let intervals: Vec<(u32, u32)> = prefixes.iter().map(v4_interval).collect();
+    merge_intervals_u32(intervals)
+        .into_iter()
+        .flat_map(|(start, end)| decompose_v4(start, end))
+        .collect()
+}
+
+fn v6_to_u128(segments: [u16; 8]) -> u128 {
+    segments.iter().fold(0u128, |acc, seg| (acc << 16) | *seg as u128)
+}
+
+fn u128_to_v6(value: u128) -> [u16; 8] {
+    let mut segments = [0u16; 8];
+    for (i, seg) in segments.iter_mut().enumerate() {
+        *seg = (value >> (16 * (7 - i))) as u16;
+    }
+    segments
+}
+
+fn v6_interval(p: &V6Prefix) -> (u128, u128) {
+    let prefix = p.prefix.min(128);
+    let ip = v6_to_u128(p.ip);
+    let netmask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+    let start = ip & netmask;
+    (start, start | !netmask)
+}
+
+fn merge_intervals_u128(mut intervals: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    intervals.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+    let mut merged: Vec<(u128, u128)> = Vec::new();
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => {
+                if end > last.1 {
+                    last.1 = end;
+                }
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn decompose_v6(start: u128, end: u128) -> Vec<V6Prefix> {
+    if start == 0 && end == u128::MAX {
+        return vec![V6Prefix { ip: [0; 8], prefix: 0 }];
+    }
+    let mut out = Vec::new();
+    let mut start = start;
+    loop {
+        let align_bits = if start == 0 { 128 } else { start.trailing_zeros() };
+        let mut prefix_len = 128u32.saturating_sub(align_bits).max(1);
+        let block_size = loop {
+            let block_size = 1u128 << (128 - prefix_len);
+            // the span from start to end can itself be up to 2^128, which
+            // doesn't fit in u128, so compare via the distance to end instead
+            if block_size - 1 <= end - start {
+                break block_size;
+            }
+            prefix_len += 1;
+        };
+        out.push(V6Prefix { ip: u128_to_v6(start), prefix: prefix_len as u8 });
+        if block_size - 1 >= end - start {
+            break;
+        }
+        start += block_size;
+    }
+    out
+}
+
+fn aggregate_v6(prefixes: Vec<V6Prefix>) -> Vec<V6Prefix> {
+    let intervals: Vec<(u128, u128)> = prefixes.iter().map(v6_interval).collect();
+    merge_intervals_u128(intervals)
+        .into_iter()
+        .flat_map(|(start, end)| decompose_v6(start, end))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(s: &str) -> V4Prefix {
+        V4Prefix::from_str(s).unwrap()
+    }
+
+    fn v6(s: &str) -> V6Prefix {
+        V6Prefix::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn aggregate_v4_merges_adjacent_halves_into_parent() {
+        let out = aggregate_v4(vec![v4("10.0.0.0/25"), v4("10.0.0.128/25")]);
+        assert_eq!(out, vec![v4("10.0.0.0/24")]);
+    }
+
+    #[test]
+    fn aggregate_v4_drops_nested_prefix() {
+        let out = aggregate_v4(vec![v4("10.0.0.0/24"), v4("10.0.0.64/28")]);
+        assert_eq!(out, vec![v4("10.0.0.0/24")]);
+    }
+
+    #[test]
+    fn aggregate_v4_full_range_collapses_to_slash_zero() {
+        let out = aggregate_v4(vec![v4("0.0.0.0/1"), v4("128.0.0.0/1")]);
+        assert_eq!(out, vec![v4("0.0.0.0/0")]);
+    }
+
+    #[test]
+    fn aggregate_v4_leaves_disjoint_prefixes_alone() {
+        let out = aggregate_v4(vec![v4("10.0.0.0/24"), v4("192.168.0.0/24")]);
+        assert_eq!(out, vec![v4("10.0.0.0/24"), v4("192.168.0.0/24")]);
+    }
+
+    #[test]
+    fn aggregate_v6_merges_adjacent_halves_into_parent() {
+        let out = aggregate_v6(vec![v6("2001:db8::/33"), v6("2001:db8:8000::/33")]);
+        assert_eq!(out, vec![v6("2001:db8::/32")]);
+    }
+
+    #[test]
+    fn aggregate_v6_drops_nested_prefix() {
+        let out = aggregate_v6(vec![v6("2001:db8::/32"), v6("2001:db8::/48")]);
+        assert_eq!(out, vec![v6("2001:db8::/32")]);
+    }
+
+    #[test]
+    fn aggregate_v6_full_range_collapses_to_slash_zero() {
+        // exercises decompose_v6's full-range case: this merged interval
+        // spans 2^128 addresses, one more than a u128 can count
+        let out = aggregate_v6(vec![v6("::/1"), v6("8000::/1")]);
+        assert_eq!(out, vec![v6("::/0")]);
+    }
+
+    #[test]
+    fn aggregate_v6_near_full_range_exercises_overflow_guard() {
+        // leaves out the top /4 (f000::/4), so the merged span starts at 0
+        // and is split into blocks up to 2^127 wide without overflowing
+        // when stepping from one block to the next
+        let out = aggregate_v6(vec![v6("::/1"), v6("8000::/2"), v6("c000::/3"), v6("e000::/4")]);
+        let expected = vec![v6("::/1"), v6("8000::/2"), v6("c000::/3"), v6("e000::/4")];
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn aggregate_free_fn_keeps_v4_and_v6_separate_and_orders_v4_first() {
+        let out = aggregate(vec![
+            IpPrefix::V6(v6("2001:db8::/32")),
+            IpPrefix::V4(v4("10.0.0.0/25")),
+            IpPrefix::V4(v4("10.0.0.128/25")),
+        ]);
+        assert_eq!(
+            out,
+            vec![IpPrefix::V4(v4("10.0.0.0/24")), IpPrefix::V6(v6("2001:db8::/32"))]
+        );
+    }
+
+    #[test]
+    fn ipset_restore_script_creates_both_families_and_routes_prefixes() {
+        let script = ipset_restore_script(&[IpPrefix::V4(v4("10.0.0.0/24")), IpPrefix::V6(v6("2001:db8::/32"))]);
+        assert!(script.contains("create bigblocker_v4 hash:net family inet -exist\n"));
+        assert!(script.contains("create bigblocker_v6 hash:net family inet6 -exist\n"));
+        assert!(script.contains("add bigblocker_v4 10.0.0.0/24\n"));
+        assert!(script.contains("add bigblocker_v6 2001:db8::/32\n"));
+    }
+
+    #[test]
+    fn nftables_script_routes_prefixes_to_matching_set() {
+        let script = nftables_script(&[IpPrefix::V4(v4("10.0.0.0/24")), IpPrefix::V6(v6("2001:db8::/32"))]);
+        assert!(script.contains("table inet bigblocker"));
+        assert!(script.contains("set bigblocker_v4"));
+        assert!(script.contains("set bigblocker_v6"));
+        assert!(script.contains("elements = { 10.0.0.0/24 }"));
+        assert!(script.contains("elements = { 2001:db8::/32 }"));
+    }
+
+    #[test]
+    fn prefix_filter_unset_field_admits_everything() {
+        let filter = PrefixFilter::default();
+        assert!(filter.matches(Some("us-east-1"), Some("EC2"), None));
+        assert!(filter.matches(None, None, None));
+    }
+
+    #[test]
+    fn prefix_filter_set_field_requires_membership() {
+        let filter = PrefixFilter {
+            regions: Some(["us-east-1".to_string()].into_iter().collect()),
+            services: None,
+            scopes: None,
+        };
+        assert!(filter.matches(Some("us-east-1"), None, None));
+        assert!(!filter.matches(Some("eu-west-1"), None, None));
+    }
+
+    #[test]
+    fn prefix_filter_set_field_rejects_missing_value() {
+        let filter = PrefixFilter {
+            regions: None,
+            services: None,
+            scopes: Some(["view".to_string()].into_iter().collect()),
+        };
+        assert!(!filter.matches(Some("us-east-1"), Some("EC2"), None));
+    }
+
+    #[test]
+    fn prefix_filter_empty_set_admits_nothing() {
+        let filter = PrefixFilter {
+            regions: Some(std::collections::HashSet::new()),
+            services: None,
+            scopes: None,
+        };
+        assert!(!filter.matches(Some("us-east-1"), None, None));
+    }
+}
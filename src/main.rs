@@ -1,6 +1,13 @@
 #[macro_use]
 extern crate serde_derive;
-use big_blocker::{AWSRange, Blocker, GoogleRange, Range};
+use big_blocker::{
+    aggregate, AWSRange, Backend, BlockError, Blocker, Cache, Config, DynamicRange, GoogleRange, IpPrefix,
+    PrefixFilter, Range,
+};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug, Clone, Serialize, Deserialize)]
@@ -9,48 +16,198 @@ pub struct Args {
     pub block: Vec<String>,
     #[structopt(short, long)]
     pub reset: bool,
+    /// firewall backend to use: iptables, ipset, or nftables
+    #[structopt(long, default_value = "iptables")]
+    pub backend: BackendArg,
+    /// path to a TOML config listing `[[provider]]` feeds; when present,
+    /// supersedes the built-in amazon/google cases
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+    /// keep running, periodically re-fetching and reconciling the delta
+    #[structopt(long)]
+    pub daemon: bool,
+    /// seconds between refreshes in daemon mode
+    #[structopt(long, default_value = "3600")]
+    pub interval: u64,
+    /// where daemon mode persists the last-applied prefix set
+    #[structopt(long, default_value = "/var/lib/big_blocker/state.json")]
+    pub state_file: PathBuf,
+    /// only block prefixes in one of these regions (repeatable)
+    #[structopt(long)]
+    pub region: Vec<String>,
+    /// only block prefixes for one of these services (repeatable)
+    #[structopt(long)]
+    pub service: Vec<String>,
+    /// only block prefixes in one of these scopes (repeatable; only Google
+    /// feeds carry a scope, so setting this drops every Amazon prefix)
+    #[structopt(long)]
+    pub scope: Vec<String>,
+    /// directory to cache fetched feeds in, keyed by provider; enables
+    /// conditional (`If-None-Match`) refetching and offline fallback
+    #[structopt(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// bypass the cache and always refetch, even with `--cache-dir` set
+    #[structopt(long)]
+    pub force_refresh: bool,
+}
+
+/// builds the `PrefixFilter` for this run: a flag left unset admits everything
+fn build_filter(args: &Args) -> PrefixFilter {
+    fn allow_set(values: &[String]) -> Option<HashSet<String>> {
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().cloned().collect())
+        }
+    }
+    PrefixFilter {
+        regions: allow_set(&args.region),
+        services: allow_set(&args.service),
+        scopes: allow_set(&args.scope),
+    }
+}
+
+/// thin `FromStr` wrapper so `Backend` can be parsed from the CLI
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BackendArg(pub Backend);
+
+impl FromStr for BackendArg {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "iptables" => Ok(BackendArg(Backend::Iptables)),
+            "ipset" => Ok(BackendArg(Backend::Ipset)),
+            "nftables" => Ok(BackendArg(Backend::Nftables)),
+            other => Err(format!("unknown backend: {}", other)),
+        }
+    }
 }
 // amazon address: https://ip-ranges.amazonaws.com/ip-ranges.json
 // google address: https://www.gstatic.com/ipranges/goog.json
 // google cloud addresses: https://www.gstatic.com/ipranges/cloud.json
 
+/// fetches one feed, going through `cache` (conditional `If-None-Match`
+/// request, falling back to the cached body on `304`) when one is
+/// configured, or a plain request otherwise. Returns the body plus whether
+/// it's unchanged from what's already cached (same `syncToken`, or a 304).
+async fn fetch_body(
+    client: &reqwest::Client,
+    url: &str,
+    cache: Option<&Cache>,
+    key: &str,
+    force_refresh: bool,
+) -> Result<(String, bool), BlockError> {
+    match cache {
+        Some(cache) => {
+            let fetched = cache.fetch(client, url, key, force_refresh).await?;
+            Ok((fetched.body, fetched.unchanged))
+        }
+        None => Ok((client.get(url).send().await?.text().await?, false)),
+    }
+}
+
+/// fetches every feed selected by `args` (the `--config` registry if given,
+/// otherwise the built-in amazon/google cases), returning the combined,
+/// aggregated (see [`aggregate`]) prefix list and whether every fetched feed
+/// was unchanged since the last run. Shared by the one-shot path and the
+/// daemon's refresh tick.
+async fn fetch_all(args: &Args) -> Result<(Vec<IpPrefix>, bool), BlockError> {
+    let filter = build_filter(args);
+    let client = reqwest::Client::new();
+    let cache = args.cache_dir.clone().map(Cache::new);
+    let mut prefixes = Vec::new();
+    let mut any_feed = false;
+    let mut all_unchanged = true;
+
+    let mut note = |unchanged: bool| {
+        any_feed = true;
+        all_unchanged = all_unchanged && unchanged;
+    };
+
+    if let Some(config_path) = &args.config {
+        let config = Config::from_file(config_path)?;
+        for provider in config.providers {
+            let key = provider.name.clone();
+            let (body, unchanged) =
+                fetch_body(&client, &provider.url, cache.as_ref(), &key, args.force_refresh).await?;
+            note(unchanged);
+            let value: serde_json::Value = serde_json::from_str(&body)?;
+            let range = DynamicRange::new(provider.name, provider.mapping, value);
+            prefixes.extend(range.prefixes_filtered(&filter)?);
+        }
+        return Ok((aggregate(prefixes), any_feed && all_unchanged));
+    }
+
+    if args.block.contains(&String::from("amazon")) {
+        let (body, unchanged) = fetch_body(
+            &client,
+            "https://ip-ranges.amazonaws.com/ip-ranges.json",
+            cache.as_ref(),
+            "amazon",
+            args.force_refresh,
+        )
+        .await?;
+        note(unchanged);
+        let aws_range: AWSRange = serde_json::from_str(&body)?;
+        prefixes.extend(aws_range.prefixes_filtered(&filter)?);
+    }
+
+    if args.block.contains(&String::from("google")) {
+        let (cloud_body, cloud_unchanged) = fetch_body(
+            &client,
+            "https://www.gstatic.com/ipranges/cloud.json",
+            cache.as_ref(),
+            "google-cloud",
+            args.force_refresh,
+        )
+        .await?;
+        note(cloud_unchanged);
+        let cloud_range: GoogleRange = serde_json::from_str(&cloud_body)?;
+        prefixes.extend(cloud_range.prefixes_filtered(&filter)?);
+
+        let (goog_body, goog_unchanged) = fetch_body(
+            &client,
+            "https://www.gstatic.com/ipranges/goog.json",
+            cache.as_ref(),
+            "google",
+            args.force_refresh,
+        )
+        .await?;
+        note(goog_unchanged);
+        let google_range: GoogleRange = serde_json::from_str(&goog_body)?;
+        prefixes.extend(google_range.prefixes_filtered(&filter)?);
+    }
+
+    Ok((aggregate(prefixes), any_feed && all_unchanged))
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::from_args();
-    if args.block.is_empty() && !args.reset {
+    if args.block.is_empty() && args.config.is_none() && !args.reset {
         eprintln!("pointless not the block anything");
     }
+    let backend = args.backend.0;
     if args.reset {
-        Blocker::unblock_all().await?;
+        Blocker::unblock_all(backend).await?;
     }
-    if args.block.contains(&String::from("amazon")) {
-        let aws_range: AWSRange = serde_json::from_str(
-            &reqwest::get("https://ip-ranges.amazonaws.com/ip-ranges.json")
-                .await?
-                .text()
-                .await?,
-        )?;
-        let blocker: Blocker = Blocker::new(aws_range.prefixes().unwrap(), false);
-        blocker.block().await.unwrap();
+
+    if args.daemon {
+        let blocker = Blocker::new(Vec::new(), false, backend);
+        let interval = Duration::from_secs(args.interval);
+        let state_file = args.state_file.clone();
+        big_blocker::daemon::run(blocker, interval, state_file, || async {
+            let (prefixes, unchanged) = fetch_all(&args).await?;
+            Ok(if unchanged { None } else { Some(prefixes) })
+        })
+        .await?;
+        return Ok(());
     }
 
-    if args.block.contains(&String::from("google")) {
-        let cloud_blocker = Blocker::new(serde_json::from_str::<GoogleRange>(
-            &reqwest::get("https://www.gstatic.com/ipranges/cloud.json")
-                .await?
-                .text()
-                .await?,
-        )?.prefixes().unwrap(), false);
-        cloud_blocker.block().await.unwrap();
-        let google_range: GoogleRange = serde_json::from_str(
-            &reqwest::get("https://www.gstatic.com/ipranges/goog.json")
-                .await?
-                .text()
-                .await?,
-        )?;
-        let blocker: Blocker = Blocker::new(google_range.prefixes().unwrap(), false);
-        blocker.block().await.unwrap();
-        
+    let (prefixes, _unchanged) = fetch_all(&args).await?;
+    if !prefixes.is_empty() {
+        let blocker = Blocker::new(prefixes, false, backend);
+        blocker.block().await?;
     }
     Ok(())
-}
\ No newline at end of file
+}